@@ -0,0 +1,39 @@
+// This file is part of Substrate.
+
+// Copyright (C) Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! # Running
+//! Running this fuzzer can be done with `cargo hfuzz run decode_and_validate_message`. `honggfuzz`
+//! CLI options can be used by setting `HFUZZ_RUN_ARGS`, such as `-n 4` to use 4 threads.
+//!
+//! # Debugging a panic
+//! Once a panic is found, it can be debugged with
+//! `cargo hfuzz run-debug decode_and_validate_message
+//! hfuzz_workspace/decode_and_validate_message/*.fuzz`.
+
+use honggfuzz::fuzz;
+use sc_network_bitswap::decode_and_validate_message;
+
+fn main() {
+	loop {
+		fuzz!(|data: &[u8]| {
+			// Any input must be either rejected or decoded, without panicking. The limit is the
+			// handler's default.
+			let _ = decode_and_validate_message(data, 16);
+		});
+	}
+}
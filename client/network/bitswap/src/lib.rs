@@ -21,9 +21,9 @@
 //! CID is expected to reference 256-bit Blake2b transaction hash.
 
 use cid::{self, Version};
-use futures::StreamExt;
+use futures::{channel::mpsc, StreamExt};
 use libp2p_identity::PeerId;
-use log::{debug, error, trace};
+use log::{debug, error, info, trace, warn};
 use prost::Message;
 use sc_client_api::BlockBackend;
 use sc_network::{
@@ -34,8 +34,16 @@ use schema::bitswap::{
 	message::{wantlist::WantType, Block as MessageBlock, BlockPresence, BlockPresenceType},
 	Message as BitswapMessage,
 };
-use sp_runtime::traits::Block as BlockT;
-use std::{io, sync::Arc, time::Duration};
+use sp_consensus::SyncOracle;
+use sp_runtime::traits::{BlakeTwo256, Block as BlockT, Hash as HashT};
+use std::{
+	io,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+	time::{Duration, Instant},
+};
 use unsigned_varint::encode as varint_encode;
 
 mod schema;
@@ -51,14 +59,23 @@ const MAX_PACKET_SIZE: u64 = 16 * 1024 * 1024;
 /// Max number of queued responses before denying requests.
 const MAX_REQUEST_QUEUE: usize = 20;
 
-/// Max number of blocks per wantlist
+/// Default max size of a response, as per the bitswap spec.
+const MAX_RESPONSE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Default max number of wantlist entries processed per request
 const MAX_WANTED_BLOCKS: usize = 16;
 
+/// Default number of consecutive backend errors after which lookups are paused.
+const BACKEND_ERROR_THRESHOLD: u32 = 16;
+
+/// Default time lookups are paused for once the backend is degraded.
+const BACKEND_COOLDOWN: Duration = Duration::from_secs(10);
+
 /// Bitswap protocol name
 const PROTOCOL_NAME: &'static str = "/ipfs/bitswap/1.2.0";
 
 /// Prefix represents all metadata of a CID, without the actual content.
-#[derive(PartialEq, Eq, Clone, Debug)]
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
 struct Prefix {
 	/// The version of CID.
 	pub version: Version,
@@ -71,38 +88,227 @@ struct Prefix {
 }
 
 impl Prefix {
+	/// Create a new prefix.
+	pub fn new(version: Version, codec: u64, mh_type: u64, mh_len: u8) -> Self {
+		Self { version, codec, mh_type, mh_len }
+	}
+
 	/// Convert the prefix to encoded bytes.
 	pub fn to_bytes(&self) -> Vec<u8> {
-		let mut res = Vec::with_capacity(4);
-		let mut buf = varint_encode::u64_buffer();
-		let version = varint_encode::u64(self.version.into(), &mut buf);
-		res.extend_from_slice(version);
-		let mut buf = varint_encode::u64_buffer();
-		let codec = varint_encode::u64(self.codec, &mut buf);
-		res.extend_from_slice(codec);
 		let mut buf = varint_encode::u64_buffer();
-		let mh_type = varint_encode::u64(self.mh_type, &mut buf);
-		res.extend_from_slice(mh_type);
-		let mut buf = varint_encode::u64_buffer();
-		let mh_len = varint_encode::u64(self.mh_len as u64, &mut buf);
-		res.extend_from_slice(mh_len);
+		// Three `u64` varints and one `u8` varint.
+		let mut res = Vec::with_capacity(3 * buf.len() + 2);
+		res.extend_from_slice(varint_encode::u64(self.version.into(), &mut buf));
+		res.extend_from_slice(varint_encode::u64(self.codec, &mut buf));
+		res.extend_from_slice(varint_encode::u64(self.mh_type, &mut buf));
+		res.extend_from_slice(varint_encode::u64(self.mh_len as u64, &mut buf));
 		res
 	}
 }
 
+impl From<&cid::Cid> for Prefix {
+	fn from(cid: &cid::Cid) -> Self {
+		Self::new(cid.version(), cid.codec(), cid.hash().code(), cid.hash().size())
+	}
+}
+
+/// Build the CID of `data` as served by [`BitswapRequestHandler`].
+///
+/// The CID is version 1 with the given `codec` and a Blake2b-256 multihash of `data`, matching
+/// how indexed transactions are looked up.
+pub fn cid_for(data: &[u8], codec: u64) -> cid::Cid {
+	let multihash = cid::multihash::Multihash::wrap(
+		u64::from(cid::multihash::Code::Blake2b256),
+		BlakeTwo256::hash(data).as_ref(),
+	)
+	.expect("32-byte digest fits in a multihash; qed");
+	cid::Cid::new_v1(codec, multihash)
+}
+
+/// Reason a CID multihash can't be mapped to a transaction hash.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+enum MultihashError {
+	/// Multihash code is not Blake2b-256.
+	#[error("Unsupported multihash code {0:#x}.")]
+	UnsupportedCode(u64),
+
+	/// Digest length doesn't match the transaction hash length.
+	#[error("Multihash digest length mismatch: expected {expected}, got {got}.")]
+	LengthMismatch { expected: usize, got: usize },
+}
+
+/// Convert a Blake2b-256 multihash to a transaction hash.
+fn try_from_multihash<H: Default + AsMut<[u8]>>(
+	multihash: &cid::multihash::Multihash,
+) -> Result<H, MultihashError> {
+	if multihash.code() != u64::from(cid::multihash::Code::Blake2b256) {
+		return Err(MultihashError::UnsupportedCode(multihash.code()))
+	}
+
+	let mut hash = H::default();
+	let digest = multihash.digest();
+	if hash.as_mut().len() != digest.len() {
+		return Err(MultihashError::LengthMismatch {
+			expected: hash.as_mut().len(),
+			got: digest.len(),
+		})
+	}
+
+	hash.as_mut().copy_from_slice(digest);
+	Ok(hash)
+}
+
+/// Content policy consulted before serving a block.
+///
+/// Blocks rejected by the policy are treated as missing.
+pub trait ContentPolicy: Send + Sync {
+	/// Returns `true` if the block referenced by `cid` may be served.
+	fn allow(&self, cid: &cid::Cid) -> bool;
+}
+
+/// How to answer `Have` wants for blocks we don't have.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DontHavePolicy {
+	/// Only send `DontHave` if the peer set `send_dont_have`, as per the bitswap spec.
+	#[default]
+	Requested,
+	/// Always send `DontHave` in response to `Have` wants, even without `send_dont_have`.
+	AlwaysForHave,
+}
+
+/// Notification of a block served to a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockServed {
+	/// Peer the block was served to.
+	pub peer: PeerId,
+	/// CID of the served block.
+	pub cid: cid::Cid,
+	/// Size of the block data in bytes.
+	pub bytes: usize,
+}
+
+/// Notification of a wantlist received from a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WantlistReceived {
+	/// Peer that sent the wantlist.
+	pub peer: PeerId,
+	/// The received wantlist.
+	pub wantlist: ValidatedWantlist,
+}
+
+/// Health of the backend serving transactions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendStatus {
+	/// Too many consecutive backend errors, lookups are paused for a cooldown.
+	Degraded,
+	/// A lookup succeeded after the backend was degraded.
+	Recovered,
+}
+
+/// Circuit breaker pausing lookups after consecutive backend errors.
+///
+/// While open, wants are left unanswered rather than answered `DontHave`. Once the cooldown has
+/// passed, lookups are let through again and the first result decides whether the breaker
+/// closes or stays open for another cooldown.
+struct BackendBreaker {
+	/// Number of consecutive errors opening the breaker.
+	threshold: u32,
+	/// Time the breaker stays open before probing the backend again.
+	cooldown: Duration,
+	/// Number of consecutive errors so far.
+	consecutive_errors: u32,
+	/// Until when lookups are paused, if the backend is degraded.
+	open_until: Option<Instant>,
+}
+
+impl BackendBreaker {
+	fn new(threshold: u32, cooldown: Duration) -> Self {
+		Self { threshold, cooldown, consecutive_errors: 0, open_until: None }
+	}
+
+	/// Whether lookups are currently paused.
+	fn is_open(&self) -> bool {
+		self.open_until.map_or(false, |open_until| Instant::now() < open_until)
+	}
+
+	/// Record a successful lookup. Returns `true` if the backend recovered.
+	fn on_success(&mut self) -> bool {
+		self.consecutive_errors = 0;
+		self.open_until.take().is_some()
+	}
+
+	/// Record a failed lookup. Returns `true` if the backend became degraded.
+	fn on_error(&mut self) -> bool {
+		self.consecutive_errors = self.consecutive_errors.saturating_add(1);
+		if self.consecutive_errors < self.threshold {
+			return false
+		}
+
+		let was_degraded = self.open_until.is_some();
+		self.open_until = Some(Instant::now() + self.cooldown);
+		!was_degraded
+	}
+}
+
+/// Read-only handle to the total number of block bytes served since startup.
+#[derive(Debug, Clone, Default)]
+pub struct BytesServed(Arc<AtomicU64>);
+
+impl BytesServed {
+	/// Get the total number of block bytes served.
+	pub fn get(&self) -> u64 {
+		self.0.load(Ordering::Relaxed)
+	}
+
+	fn add(&self, bytes: u64) {
+		self.0.fetch_add(bytes, Ordering::Relaxed);
+	}
+}
+
 /// Bitswap request handler
 pub struct BitswapRequestHandler<B> {
 	client: Arc<dyn BlockBackend<B> + Send + Sync>,
 	request_receiver: async_channel::Receiver<IncomingRequest>,
+	/// Policy vetoing which blocks may be served.
+	content_policy: Option<Arc<dyn ContentPolicy>>,
+	/// Total number of block bytes served since startup.
+	bytes_served: BytesServed,
+	/// Sink for per-block served notifications.
+	block_served_tx: Option<mpsc::Sender<BlockServed>>,
+	/// Sink for received wantlist notifications.
+	wantlist_tx: Option<mpsc::Sender<WantlistReceived>>,
+	/// When to answer `DontHave`.
+	dont_have_policy: DontHavePolicy,
+	/// Sync status; nothing is served during major sync.
+	sync_oracle: Option<Arc<dyn SyncOracle + Send + Sync>>,
+	/// Max size of a response in bytes.
+	max_response_size: usize,
+	/// Max number of wantlist entries processed per request, the rest is ignored.
+	max_wantlist_entries: usize,
+	/// Pauses lookups while the backend keeps failing.
+	backend_breaker: BackendBreaker,
+	/// Sink for backend status notifications.
+	backend_status_tx: Option<mpsc::Sender<BackendStatus>>,
 }
 
 impl<B: BlockT> BitswapRequestHandler<B> {
 	/// Create a new [`BitswapRequestHandler`].
 	pub fn new(client: Arc<dyn BlockBackend<B> + Send + Sync>) -> (Self, ProtocolConfig) {
+		Self::with_protocol_name(client, ProtocolName::from(PROTOCOL_NAME))
+	}
+
+	/// Create a new [`BitswapRequestHandler`] serving under a custom protocol name.
+	///
+	/// Only peers using the same protocol name (e.g. `/mychain/bitswap/1.2.0` on a private
+	/// network) are able to fetch blocks from us.
+	pub fn with_protocol_name(
+		client: Arc<dyn BlockBackend<B> + Send + Sync>,
+		protocol_name: ProtocolName,
+	) -> (Self, ProtocolConfig) {
 		let (tx, request_receiver) = async_channel::bounded(MAX_REQUEST_QUEUE);
 
 		let config = ProtocolConfig {
-			name: ProtocolName::from(PROTOCOL_NAME),
+			name: protocol_name,
 			fallback_names: vec![],
 			max_request_size: MAX_PACKET_SIZE,
 			max_response_size: MAX_PACKET_SIZE,
@@ -110,7 +316,114 @@ impl<B: BlockT> BitswapRequestHandler<B> {
 			inbound_queue: Some(tx),
 		};
 
-		(Self { client, request_receiver }, config)
+		(
+			Self {
+				client,
+				request_receiver,
+				content_policy: None,
+				bytes_served: Default::default(),
+				block_served_tx: None,
+				wantlist_tx: None,
+				dont_have_policy: DontHavePolicy::default(),
+				sync_oracle: None,
+				max_response_size: MAX_RESPONSE_SIZE,
+				max_wantlist_entries: MAX_WANTED_BLOCKS,
+				backend_breaker: BackendBreaker::new(BACKEND_ERROR_THRESHOLD, BACKEND_COOLDOWN),
+				backend_status_tx: None,
+			},
+			config,
+		)
+	}
+
+	/// Only serve blocks allowed by `content_policy`.
+	pub fn with_content_policy(mut self, content_policy: Arc<dyn ContentPolicy>) -> Self {
+		self.content_policy = Some(content_policy);
+		self
+	}
+
+	/// Answer `DontHave` according to `dont_have_policy`.
+	pub fn with_dont_have_policy(mut self, dont_have_policy: DontHavePolicy) -> Self {
+		self.dont_have_policy = dont_have_policy;
+		self
+	}
+
+	/// Don't serve any blocks while `sync_oracle` reports major sync.
+	///
+	/// A node still catching up may not have the transactions it will eventually serve, so all
+	/// wants are treated as missing until the sync completes.
+	pub fn with_sync_oracle(mut self, sync_oracle: Arc<dyn SyncOracle + Send + Sync>) -> Self {
+		self.sync_oracle = Some(sync_oracle);
+		self
+	}
+
+	/// Send a [`BlockServed`] notification to `block_served_tx` for every block served.
+	///
+	/// Notifications are dropped if the channel is full.
+	pub fn with_block_served_notifications(
+		mut self,
+		block_served_tx: mpsc::Sender<BlockServed>,
+	) -> Self {
+		self.block_served_tx = Some(block_served_tx);
+		self
+	}
+
+	/// Send a [`WantlistReceived`] notification to `wantlist_tx` for every valid request.
+	///
+	/// Notifications are dropped if the channel is full.
+	pub fn with_wantlist_notifications(
+		mut self,
+		wantlist_tx: mpsc::Sender<WantlistReceived>,
+	) -> Self {
+		self.wantlist_tx = Some(wantlist_tx);
+		self
+	}
+
+	/// Refuse to send responses larger than `max_response_size` bytes.
+	///
+	/// Defaults to the 4 MiB allowed by the bitswap spec, and can't exceed the protocol's max
+	/// message size.
+	pub fn with_max_response_size(mut self, max_response_size: usize) -> Self {
+		self.max_response_size = max_response_size.min(MAX_PACKET_SIZE as usize);
+		self
+	}
+
+	/// Only process the first `max_wantlist_entries` entries of a request's wantlist.
+	///
+	/// Each entry costs a database lookup while handling the request, so this bounds the work a
+	/// single request can cause. Entries beyond the limit are ignored.
+	pub fn with_max_wantlist_entries_per_message(mut self, max_wantlist_entries: usize) -> Self {
+		self.max_wantlist_entries = max_wantlist_entries;
+		self
+	}
+
+	/// Pause lookups for `cooldown` after `threshold` consecutive backend errors.
+	///
+	/// While paused, wanted blocks are neither served nor answered `DontHave`, so peers don't
+	/// learn a wrong answer during an outage. The first lookup after the cooldown decides whether
+	/// lookups resume or stay paused for another cooldown.
+	pub fn with_backend_circuit_breaker(mut self, threshold: u32, cooldown: Duration) -> Self {
+		self.backend_breaker = BackendBreaker::new(threshold, cooldown);
+		self
+	}
+
+	/// Send a [`BackendStatus`] notification to `backend_status_tx` whenever the backend becomes
+	/// degraded or recovers.
+	///
+	/// Notifications are dropped if the channel is full.
+	pub fn with_backend_status_notifications(
+		mut self,
+		backend_status_tx: mpsc::Sender<BackendStatus>,
+	) -> Self {
+		self.backend_status_tx = Some(backend_status_tx);
+		self
+	}
+
+	/// Get a handle to the total number of block bytes served since startup.
+	///
+	/// Only the data of served blocks is counted, not presences or message framing. The handle
+	/// remains readable after the handler has been moved into [`Self::run`].
+	pub fn total_bytes_served(&self) -> BytesServed {
+		self.bytes_served.clone()
 	}
 
 	/// Run [`BitswapRequestHandler`].
@@ -119,7 +432,7 @@ impl<B: BlockT> BitswapRequestHandler<B> {
 			let IncomingRequest { peer, payload, pending_response } = request;
 
 			match self.handle_message(&peer, &payload) {
-				Ok(response) => {
+				Ok((response, served)) => {
 					let response = OutgoingResponse {
 						result: Ok(response),
 						reputation_changes: Vec::new(),
@@ -128,6 +441,9 @@ impl<B: BlockT> BitswapRequestHandler<B> {
 
 					match pending_response.send(response) {
 						Ok(()) => {
+							self.bytes_served
+								.add(served.iter().map(|block| block.bytes as u64).sum());
+							self.notify_blocks_served(served);
 							trace!(target: LOG_TARGET, "Handled bitswap request from {peer}.",)
 						},
 						Err(_) => debug!(
@@ -160,72 +476,133 @@ impl<B: BlockT> BitswapRequestHandler<B> {
 		}
 	}
 
+	/// Notify about blocks served to a peer.
+	fn notify_blocks_served(&mut self, served: Vec<BlockServed>) {
+		if let Some(block_served_tx) = self.block_served_tx.as_mut() {
+			for block in served {
+				if let Err(e) = block_served_tx.try_send(block) {
+					debug!(target: LOG_TARGET, "Failed to send block served notification: {e}");
+				}
+			}
+		}
+	}
+
+	/// Notify about a change of the backend status.
+	fn notify_backend_status(&mut self, status: BackendStatus) {
+		if let Some(backend_status_tx) = self.backend_status_tx.as_mut() {
+			if let Err(e) = backend_status_tx.try_send(status) {
+				debug!(target: LOG_TARGET, "Failed to send backend status notification: {e}");
+			}
+		}
+	}
+
 	/// Handle received Bitswap request
+	///
+	/// Returns the encoded response together with the blocks it serves.
 	fn handle_message(
 		&mut self,
 		peer: &PeerId,
 		payload: &Vec<u8>,
-	) -> Result<Vec<u8>, BitswapError> {
-		let request = schema::bitswap::Message::decode(&payload[..])?;
+	) -> Result<(Vec<u8>, Vec<BlockServed>), BitswapError> {
+		let (wantlist, rejected) = decode_and_validate_message(payload, self.max_wantlist_entries)?;
 
-		trace!(target: LOG_TARGET, "Received request: {:?} from {}", request, peer);
+		trace!(target: LOG_TARGET, "Received request: {:?} from {}", wantlist, peer);
 
-		let mut response = BitswapMessage::default();
+		if wantlist.ignored > 0 {
+			debug!(
+				target: LOG_TARGET,
+				"Ignoring {} wantlist entries from {} beyond the limit of {}",
+				wantlist.ignored, peer, self.max_wantlist_entries,
+			);
+		}
 
-		let wantlist = match request.wantlist {
-			Some(wantlist) => wantlist,
-			None => {
-				debug!(target: LOG_TARGET, "Unexpected bitswap message from {}", peer);
-				return Err(BitswapError::InvalidWantList)
-			},
-		};
+		for RejectedEntry { block, error } in rejected {
+			debug!(
+				target: LOG_TARGET,
+				"Ignoring malformed CID {:?} from {}: {}", block, peer, error,
+			);
+		}
+
+		if let Some(wantlist_tx) = self.wantlist_tx.as_mut() {
+			let notification = WantlistReceived { peer: *peer, wantlist: wantlist.clone() };
+			if let Err(e) = wantlist_tx.try_send(notification) {
+				debug!(target: LOG_TARGET, "Failed to send wantlist notification: {e}");
+			}
+		}
 
-		if wantlist.entries.len() > MAX_WANTED_BLOCKS {
-			trace!(target: LOG_TARGET, "Ignored request: too many entries");
-			return Err(BitswapError::TooManyEntries)
+		let mut response = BitswapMessage::default();
+		let mut served = Vec::new();
+
+		let major_syncing = self.sync_oracle.as_ref().map_or(false, |o| o.is_major_syncing());
+		if major_syncing {
+			debug!(target: LOG_TARGET, "Not serving blocks to {} during major sync", peer);
 		}
 
 		for entry in wantlist.entries {
-			let cid = match cid::Cid::read_bytes(entry.block.as_slice()) {
-				Ok(cid) => cid,
-				Err(e) => {
-					trace!(target: LOG_TARGET, "Bad CID {:?}: {:?}", entry.block, e);
-					continue
-				},
-			};
+			let cid = entry.cid;
+
+			// Every request is answered on its own, so there is never an earlier want still
+			// pending for a cancel to remove. A wantlist with only cancels (including an
+			// incremental "cancel everything") is answered with an empty response.
+			if entry.cancel {
+				trace!(target: LOG_TARGET, "Ignoring cancelled CID {} from {}", cid, peer);
+				continue
+			}
 
-			if cid.version() != cid::Version::V1 ||
-				cid.hash().code() != u64::from(cid::multihash::Code::Blake2b256) ||
-				cid.hash().size() != 32
-			{
+			if cid.version() != cid::Version::V1 {
 				debug!(target: LOG_TARGET, "Ignoring unsupported CID {}: {}", peer, cid);
 				continue
 			}
 
-			let mut hash = B::Hash::default();
-			hash.as_mut().copy_from_slice(&cid.hash().digest()[0..32]);
-			let transaction = match self.client.indexed_transaction(hash) {
-				Ok(ex) => ex,
+			let hash = match try_from_multihash::<B::Hash>(cid.hash()) {
+				Ok(hash) => hash,
 				Err(e) => {
-					error!(target: LOG_TARGET, "Error retrieving transaction {}: {}", hash, e);
-					None
+					debug!(target: LOG_TARGET, "Ignoring unsupported CID {}: {}: {}", peer, cid, e);
+					continue
 				},
 			};
+			let transaction = if major_syncing {
+				None
+			} else if self.content_policy.as_ref().map_or(true, |p| p.allow(&cid)) {
+				// Don't claim `DontHave` when the backend is failing, the peer would otherwise
+				// stop asking us for a transaction we may well have.
+				if self.backend_breaker.is_open() {
+					trace!(target: LOG_TARGET, "Backend degraded, not looking up CID {}", cid);
+					continue
+				}
+
+				match self.client.indexed_transaction(hash) {
+					Ok(ex) => {
+						if self.backend_breaker.on_success() {
+							info!(target: LOG_TARGET, "Backend recovered, resuming lookups");
+							self.notify_backend_status(BackendStatus::Recovered);
+						}
+						ex
+					},
+					Err(e) => {
+						error!(target: LOG_TARGET, "Error retrieving transaction {}: {}", hash, e);
+						if self.backend_breaker.on_error() {
+							warn!(target: LOG_TARGET, "Backend degraded, pausing lookups");
+							self.notify_backend_status(BackendStatus::Degraded);
+						}
+						continue
+					},
+				}
+			} else {
+				debug!(target: LOG_TARGET, "CID {} blocked by content policy", cid);
+				None
+			};
 
 			match transaction {
 				Some(transaction) => {
 					trace!(target: LOG_TARGET, "Found CID {:?}, hash {:?}", cid, hash);
 
-					if entry.want_type == WantType::Block as i32 {
-						let prefix = Prefix {
-							version: cid.version(),
-							codec: cid.codec(),
-							mh_type: cid.hash().code(),
-							mh_len: cid.hash().size(),
-						};
-						response
-							.payload
-							.push(MessageBlock { prefix: prefix.to_bytes(), data: transaction });
+					if entry.want_block {
+						served.push(BlockServed { peer: *peer, cid, bytes: transaction.len() });
+						response.payload.push(MessageBlock {
+							prefix: Prefix::from(&cid).to_bytes(),
+							data: transaction,
+						});
 					} else {
 						response.block_presences.push(BlockPresence {
 							r#type: BlockPresenceType::Have as i32,
@@ -236,7 +613,11 @@ impl<B: BlockT> BitswapRequestHandler<B> {
 				None => {
 					trace!(target: LOG_TARGET, "Missing CID {:?}, hash {:?}", cid, hash);
 
-					if entry.send_dont_have {
+					let send_dont_have = entry.send_dont_have ||
+						(self.dont_have_policy == DontHavePolicy::AlwaysForHave &&
+							!entry.want_block);
+
+					if send_dont_have {
 						response.block_presences.push(BlockPresence {
 							r#type: BlockPresenceType::DontHave as i32,
 							cid: cid.to_bytes(),
@@ -246,8 +627,81 @@ impl<B: BlockT> BitswapRequestHandler<B> {
 			}
 		}
 
-		Ok(response.encode_to_vec())
+		// The peer would reject the response anyway, don't waste bandwidth sending it.
+		let response_len = response.encoded_len();
+		if response_len > self.max_response_size {
+			return Err(BitswapError::ResponseTooLarge(response_len))
+		}
+
+		Ok((response.encode_to_vec(), served))
+	}
+}
+
+/// Wantlist of a decoded and validated bitswap request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidatedWantlist {
+	/// Wanted blocks.
+	pub entries: Vec<ValidatedEntry>,
+	/// Number of entries ignored for exceeding the per-request limit.
+	pub ignored: usize,
+	/// Whether this is the full wantlist rather than an update.
+	pub full: bool,
+}
+
+/// Entry of a [`ValidatedWantlist`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidatedEntry {
+	/// CID of the wanted block.
+	pub cid: cid::Cid,
+	/// Whether the block itself is wanted, rather than just its presence.
+	pub want_block: bool,
+	/// Whether to answer `DontHave` if the block is missing.
+	pub send_dont_have: bool,
+	/// Whether this cancels an earlier want for the block.
+	pub cancel: bool,
+}
+
+/// Wantlist entry of a bitswap request that failed validation.
+#[derive(Debug)]
+pub struct RejectedEntry {
+	/// Raw CID bytes of the entry.
+	pub block: Vec<u8>,
+	/// Why the entry was rejected.
+	pub error: BitswapError,
+}
+
+/// Decode an inbound bitswap request and validate its wantlist.
+///
+/// Only the first `max_entries` entries are validated, the rest is counted as ignored. Entries
+/// with a malformed CID are returned separately, so the rest of the request can still be served.
+/// This is free of side effects, so untrusted input can be checked (or fuzzed) on its own.
+pub fn decode_and_validate_message(
+	bytes: &[u8],
+	max_entries: usize,
+) -> Result<(ValidatedWantlist, Vec<RejectedEntry>), BitswapError> {
+	let request = schema::bitswap::Message::decode(bytes)?;
+
+	let mut wantlist = request.wantlist.ok_or(BitswapError::InvalidWantList)?;
+
+	let ignored = wantlist.entries.len().saturating_sub(max_entries);
+	wantlist.entries.truncate(max_entries);
+
+	let mut entries = Vec::with_capacity(wantlist.entries.len());
+	let mut rejected = Vec::new();
+
+	for entry in wantlist.entries {
+		match cid::Cid::read_bytes(entry.block.as_slice()) {
+			Ok(cid) => entries.push(ValidatedEntry {
+				cid,
+				want_block: entry.want_type == WantType::Block as i32,
+				send_dont_have: entry.send_dont_have,
+				cancel: entry.cancel,
+			}),
+			Err(e) => rejected.push(RejectedEntry { block: entry.block, error: e.into() }),
+		}
 	}
+
+	Ok((ValidatedWantlist { entries, ignored, full: wantlist.full }, rejected))
 }
 
 /// Bitswap protocol error.
@@ -281,9 +735,9 @@ pub enum BitswapError {
 	#[error("Invalid WANT list.")]
 	InvalidWantList,
 
-	/// Too many blocks requested.
-	#[error("Too many block entries in the request.")]
-	TooManyEntries,
+	/// Response doesn't fit in a single message.
+	#[error("Response of {0} bytes exceeds the maximum message size.")]
+	ResponseTooLarge(usize),
 }
 
 #[cfg(test)]
@@ -296,9 +750,175 @@ mod tests {
 		Message as BitswapMessage,
 	};
 	use sp_consensus::BlockOrigin;
-	use sp_runtime::codec::Encode;
+	use sp_runtime::{codec::Encode, generic::SignedBlock, traits::NumberFor, Justifications};
+	use std::sync::atomic::AtomicBool;
 	use substrate_test_runtime::ExtrinsicBuilder;
-	use substrate_test_runtime_client::{self, prelude::*, TestClientBuilder};
+	use substrate_test_runtime_client::{self, prelude::*, runtime::Block, TestClientBuilder};
+
+	#[test]
+	fn prefix_to_bytes() {
+		let prefix =
+			Prefix::new(Version::V1, 0x70, u64::from(cid::multihash::Code::Blake2b256), 32);
+		assert_eq!(prefix.to_bytes(), vec![0x01, 0x70, 0xa0, 0xe4, 0x02, 0x20]);
+	}
+
+	#[test]
+	fn prefix_from_cid() {
+		let cid = cid::Cid::new_v1(
+			0x55,
+			cid::multihash::Multihash::wrap(u64::from(cid::multihash::Code::Sha2_256), &[0; 32])
+				.unwrap(),
+		);
+		assert_eq!(
+			Prefix::from(&cid),
+			Prefix::new(Version::V1, 0x55, u64::from(cid::multihash::Code::Sha2_256), 32),
+		);
+	}
+
+	#[test]
+	fn decode_valid_message() {
+		let cid = cid::Cid::new_v1(
+			0x70,
+			cid::multihash::Multihash::wrap(
+				u64::from(cid::multihash::Code::Blake2b256),
+				&[0x13; 32],
+			)
+			.unwrap(),
+		);
+		let message = BitswapMessage {
+			wantlist: Some(Wantlist {
+				entries: vec![Entry {
+					block: cid.to_bytes(),
+					want_type: WantType::Have as i32,
+					send_dont_have: true,
+					..Default::default()
+				}],
+				full: true,
+			}),
+			..Default::default()
+		};
+
+		let (wantlist, rejected) =
+			decode_and_validate_message(&message.encode_to_vec(), MAX_WANTED_BLOCKS).unwrap();
+		assert!(rejected.is_empty());
+		assert_eq!(
+			wantlist,
+			ValidatedWantlist {
+				entries: vec![ValidatedEntry {
+					cid,
+					want_block: false,
+					send_dont_have: true,
+					cancel: false,
+				}],
+				ignored: 0,
+				full: true,
+			},
+		);
+	}
+
+	#[test]
+	fn decode_adversarial_messages() {
+		// Truncated varint.
+		assert!(matches!(
+			decode_and_validate_message(&[0x0a, 0xff], MAX_WANTED_BLOCKS),
+			Err(BitswapError::DecodeProto(_)),
+		));
+
+		// No wantlist.
+		assert!(matches!(
+			decode_and_validate_message(
+				&BitswapMessage::default().encode_to_vec(),
+				MAX_WANTED_BLOCKS
+			),
+			Err(BitswapError::InvalidWantList),
+		));
+
+		// Huge repeated field.
+		let message = BitswapMessage {
+			wantlist: Some(Wantlist { entries: vec![Entry::default(); 10_000], full: false }),
+			..Default::default()
+		};
+		let (wantlist, rejected) =
+			decode_and_validate_message(&message.encode_to_vec(), MAX_WANTED_BLOCKS).unwrap();
+		assert!(wantlist.entries.is_empty());
+		assert_eq!(wantlist.ignored, 10_000 - MAX_WANTED_BLOCKS);
+		assert_eq!(rejected.len(), MAX_WANTED_BLOCKS);
+
+		// Malformed CID.
+		let message = BitswapMessage {
+			wantlist: Some(Wantlist {
+				entries: vec![Entry { block: vec![0x01, 0x70, 0xff], ..Default::default() }],
+				full: false,
+			}),
+			..Default::default()
+		};
+		let (wantlist, rejected) =
+			decode_and_validate_message(&message.encode_to_vec(), MAX_WANTED_BLOCKS).unwrap();
+		assert!(wantlist.entries.is_empty());
+		assert_eq!(rejected.len(), 1);
+		assert_eq!(rejected[0].block, vec![0x01, 0x70, 0xff]);
+		assert!(matches!(rejected[0].error, BitswapError::BadCid(_)));
+	}
+
+	#[test]
+	fn cid_for_data() {
+		assert_eq!(
+			cid_for(&[], 0x55).to_string(),
+			"bafk2bzaceahfouoae3suhmxivmxlayez3kq5dzo7i53y654h7kvultprf7r2q",
+		);
+		assert_eq!(
+			cid_for(&[0x13, 0x37, 0x13, 0x38], 0x70).to_string(),
+			"bafykbzacebvql7r2xk5wtha3gmk5v6hcih4wpu474f7ypg3tzlecjijmpjbi2",
+		);
+	}
+
+	#[test]
+	fn multihash_to_hash() {
+		let multihash = cid::multihash::Multihash::wrap(
+			u64::from(cid::multihash::Code::Blake2b256),
+			&[0x13; 32],
+		)
+		.unwrap();
+		assert_eq!(try_from_multihash::<sp_core::H256>(&multihash), Ok([0x13; 32].into()));
+	}
+
+	#[test]
+	fn multihash_with_unsupported_code() {
+		let multihash =
+			cid::multihash::Multihash::wrap(u64::from(cid::multihash::Code::Sha2_256), &[0x13; 32])
+				.unwrap();
+		assert_eq!(
+			try_from_multihash::<sp_core::H256>(&multihash),
+			Err(MultihashError::UnsupportedCode(u64::from(cid::multihash::Code::Sha2_256))),
+		);
+	}
+
+	#[test]
+	fn multihash_with_wrong_length() {
+		let multihash = cid::multihash::Multihash::wrap(
+			u64::from(cid::multihash::Code::Blake2b256),
+			&[0x13; 16],
+		)
+		.unwrap();
+		assert_eq!(
+			try_from_multihash::<sp_core::H256>(&multihash),
+			Err(MultihashError::LengthMismatch { expected: 32, got: 16 }),
+		);
+	}
+
+	#[test]
+	fn protocol_name() {
+		let client = Arc::new(substrate_test_runtime_client::new());
+
+		let (_, config) = BitswapRequestHandler::new(client.clone());
+		assert_eq!(config.name, ProtocolName::from("/ipfs/bitswap/1.2.0"));
+
+		let (_, config) = BitswapRequestHandler::with_protocol_name(
+			client,
+			ProtocolName::from("/mychain/bitswap/1.2.0"),
+		);
+		assert_eq!(config.name, ProtocolName::from("/mychain/bitswap/1.2.0"));
+	}
 
 	#[tokio::test]
 	async fn undecodeable_message() {
@@ -410,8 +1030,9 @@ mod tests {
 			.await
 			.unwrap();
 
+		// Entries beyond the limit are ignored rather than failing the request.
 		if let Ok(OutgoingResponse { result, reputation_changes, sent_feedback }) = rx.await {
-			assert_eq!(result, Err(()));
+			assert_eq!(result, Ok(vec![]));
 			assert_eq!(reputation_changes, Vec::new());
 			assert!(sent_feedback.is_none());
 		} else {
@@ -524,4 +1145,771 @@ mod tests {
 			panic!("invalid event received");
 		}
 	}
+
+	#[tokio::test]
+	async fn total_bytes_served() {
+		let mut client = TestClientBuilder::with_tx_storage(u32::MAX).build();
+		let mut block_builder = client.new_block(Default::default()).unwrap();
+
+		let data = [vec![0x13; 1024], vec![0x37; 1024]];
+		for (nonce, data) in data.iter().enumerate() {
+			let ext = ExtrinsicBuilder::new_indexed_call(data.clone()).nonce(nonce as u64).build();
+			block_builder.push(ext).unwrap();
+		}
+		let block = block_builder.build().unwrap().block;
+
+		client.import(BlockOrigin::File, block).await.unwrap();
+
+		let (bitswap, config) = BitswapRequestHandler::new(Arc::new(client));
+		let bytes_served = bitswap.total_bytes_served();
+
+		tokio::spawn(async move { bitswap.run().await });
+
+		let (tx, rx) = oneshot::channel();
+		config
+			.inbound_queue
+			.unwrap()
+			.send(IncomingRequest {
+				peer: PeerId::random(),
+				payload: BitswapMessage {
+					wantlist: Some(Wantlist {
+						entries: data
+							.iter()
+							.map(|data| Entry {
+								block: cid_for(data, 0x70).to_bytes(),
+								..Default::default()
+							})
+							.collect(),
+						full: false,
+					}),
+					..Default::default()
+				}
+				.encode_to_vec(),
+				pending_response: tx,
+			})
+			.await
+			.unwrap();
+
+		if let Ok(OutgoingResponse { result, .. }) = rx.await {
+			assert!(result.is_ok());
+			assert_eq!(bytes_served.get(), 2048);
+		} else {
+			panic!("invalid event received");
+		}
+	}
+
+	#[tokio::test]
+	async fn transaction_blocked_by_content_policy() {
+		struct Deny;
+
+		impl ContentPolicy for Deny {
+			fn allow(&self, _cid: &cid::Cid) -> bool {
+				false
+			}
+		}
+
+		let mut client = TestClientBuilder::with_tx_storage(u32::MAX).build();
+		let mut block_builder = client.new_block(Default::default()).unwrap();
+
+		let ext = ExtrinsicBuilder::new_indexed_call(vec![0x13, 0x37, 0x13, 0x38]).build();
+		block_builder.push(ext).unwrap();
+		let block = block_builder.build().unwrap().block;
+
+		client.import(BlockOrigin::File, block).await.unwrap();
+
+		let (bitswap, config) = BitswapRequestHandler::new(Arc::new(client));
+		let bitswap = bitswap.with_content_policy(Arc::new(Deny));
+
+		tokio::spawn(async move { bitswap.run().await });
+
+		let cid = cid_for(&[0x13, 0x37, 0x13, 0x38], 0x70);
+
+		let (tx, rx) = oneshot::channel();
+		config
+			.inbound_queue
+			.unwrap()
+			.send(IncomingRequest {
+				peer: PeerId::random(),
+				payload: BitswapMessage {
+					wantlist: Some(Wantlist {
+						entries: vec![Entry {
+							block: cid.to_bytes(),
+							send_dont_have: true,
+							..Default::default()
+						}],
+						full: false,
+					}),
+					..Default::default()
+				}
+				.encode_to_vec(),
+				pending_response: tx,
+			})
+			.await
+			.unwrap();
+
+		if let Ok(OutgoingResponse { result, .. }) = rx.await {
+			let response =
+				schema::bitswap::Message::decode(&result.expect("fetch to succeed")[..]).unwrap();
+			assert!(response.payload.is_empty());
+			assert_eq!(
+				response.block_presences,
+				vec![BlockPresence {
+					r#type: BlockPresenceType::DontHave as i32,
+					cid: cid.to_bytes()
+				}],
+			);
+		} else {
+			panic!("invalid event received");
+		}
+	}
+
+	#[tokio::test]
+	async fn block_served_notification() {
+		let mut client = TestClientBuilder::with_tx_storage(u32::MAX).build();
+		let mut block_builder = client.new_block(Default::default()).unwrap();
+
+		let ext = ExtrinsicBuilder::new_indexed_call(vec![0x13, 0x37, 0x13, 0x38]).build();
+		block_builder.push(ext).unwrap();
+		let block = block_builder.build().unwrap().block;
+
+		client.import(BlockOrigin::File, block).await.unwrap();
+
+		let (block_served_tx, mut block_served_rx) = mpsc::channel(16);
+		let (bitswap, config) = BitswapRequestHandler::new(Arc::new(client));
+		let bitswap = bitswap.with_block_served_notifications(block_served_tx);
+
+		tokio::spawn(async move { bitswap.run().await });
+
+		let peer = PeerId::random();
+		let cid = cid_for(&[0x13, 0x37, 0x13, 0x38], 0x70);
+
+		let (tx, rx) = oneshot::channel();
+		config
+			.inbound_queue
+			.unwrap()
+			.send(IncomingRequest {
+				peer,
+				payload: BitswapMessage {
+					wantlist: Some(Wantlist {
+						entries: vec![Entry { block: cid.to_bytes(), ..Default::default() }],
+						full: false,
+					}),
+					..Default::default()
+				}
+				.encode_to_vec(),
+				pending_response: tx,
+			})
+			.await
+			.unwrap();
+
+		assert!(rx.await.unwrap().result.is_ok());
+		assert_eq!(block_served_rx.try_next().unwrap(), Some(BlockServed { peer, cid, bytes: 4 }));
+		assert!(block_served_rx.try_next().is_err());
+	}
+
+	#[tokio::test]
+	async fn dont_have_policy() {
+		for (policy, expected_presences) in
+			[(DontHavePolicy::Requested, 0), (DontHavePolicy::AlwaysForHave, 1)]
+		{
+			let client = TestClientBuilder::with_tx_storage(u32::MAX).build();
+
+			let (bitswap, config) = BitswapRequestHandler::new(Arc::new(client));
+			let bitswap = bitswap.with_dont_have_policy(policy);
+			tokio::spawn(async move { bitswap.run().await });
+
+			let (tx, rx) = oneshot::channel();
+			config
+				.inbound_queue
+				.unwrap()
+				.send(IncomingRequest {
+					peer: PeerId::random(),
+					payload: BitswapMessage {
+						wantlist: Some(Wantlist {
+							entries: vec![Entry {
+								block: cid::Cid::new_v1(
+									0x70,
+									cid::multihash::Multihash::wrap(
+										u64::from(cid::multihash::Code::Blake2b256),
+										&[0u8; 32],
+									)
+									.unwrap(),
+								)
+								.to_bytes(),
+								want_type: WantType::Have as i32,
+								send_dont_have: false,
+								..Default::default()
+							}],
+							full: false,
+						}),
+						..Default::default()
+					}
+					.encode_to_vec(),
+					pending_response: tx,
+				})
+				.await
+				.unwrap();
+
+			if let Ok(OutgoingResponse { result, .. }) = rx.await {
+				let response =
+					schema::bitswap::Message::decode(&result.expect("fetch to succeed")[..])
+						.unwrap();
+				assert_eq!(response.block_presences.len(), expected_presences);
+				assert!(response
+					.block_presences
+					.iter()
+					.all(|p| p.r#type == BlockPresenceType::DontHave as i32));
+			} else {
+				panic!("invalid event received");
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn transaction_not_served_during_major_sync() {
+		struct MajorSyncing;
+
+		impl SyncOracle for MajorSyncing {
+			fn is_major_syncing(&self) -> bool {
+				true
+			}
+
+			fn is_offline(&self) -> bool {
+				false
+			}
+		}
+
+		let mut client = TestClientBuilder::with_tx_storage(u32::MAX).build();
+		let mut block_builder = client.new_block(Default::default()).unwrap();
+
+		let ext = ExtrinsicBuilder::new_indexed_call(vec![0x13, 0x37, 0x13, 0x38]).build();
+		block_builder.push(ext).unwrap();
+		let block = block_builder.build().unwrap().block;
+
+		client.import(BlockOrigin::File, block).await.unwrap();
+
+		let (bitswap, config) = BitswapRequestHandler::new(Arc::new(client));
+		let bitswap = bitswap.with_sync_oracle(Arc::new(MajorSyncing));
+
+		tokio::spawn(async move { bitswap.run().await });
+
+		let cid = cid_for(&[0x13, 0x37, 0x13, 0x38], 0x70);
+
+		let (tx, rx) = oneshot::channel();
+		config
+			.inbound_queue
+			.unwrap()
+			.send(IncomingRequest {
+				peer: PeerId::random(),
+				payload: BitswapMessage {
+					wantlist: Some(Wantlist {
+						entries: vec![Entry {
+							block: cid.to_bytes(),
+							send_dont_have: true,
+							..Default::default()
+						}],
+						full: false,
+					}),
+					..Default::default()
+				}
+				.encode_to_vec(),
+				pending_response: tx,
+			})
+			.await
+			.unwrap();
+
+		if let Ok(OutgoingResponse { result, .. }) = rx.await {
+			let response =
+				schema::bitswap::Message::decode(&result.expect("fetch to succeed")[..]).unwrap();
+			assert!(response.payload.is_empty());
+			assert_eq!(
+				response.block_presences,
+				vec![BlockPresence {
+					r#type: BlockPresenceType::DontHave as i32,
+					cid: cid.to_bytes(),
+				}],
+			);
+		} else {
+			panic!("invalid event received");
+		}
+	}
+
+	#[tokio::test]
+	async fn wantlist_notification() {
+		let client = substrate_test_runtime_client::new();
+		let (wantlist_tx, mut wantlist_rx) = mpsc::channel(16);
+		let (bitswap, config) = BitswapRequestHandler::new(Arc::new(client));
+		let bitswap = bitswap.with_wantlist_notifications(wantlist_tx);
+
+		tokio::spawn(async move { bitswap.run().await });
+
+		let peer = PeerId::random();
+		let cid = cid::Cid::new_v1(
+			0x70,
+			cid::multihash::Multihash::wrap(
+				u64::from(cid::multihash::Code::Blake2b256),
+				&[0u8; 32],
+			)
+			.unwrap(),
+		);
+
+		let (tx, rx) = oneshot::channel();
+		config
+			.inbound_queue
+			.unwrap()
+			.send(IncomingRequest {
+				peer,
+				payload: BitswapMessage {
+					wantlist: Some(Wantlist {
+						entries: vec![Entry {
+							block: cid.to_bytes(),
+							want_type: WantType::Have as i32,
+							..Default::default()
+						}],
+						full: false,
+					}),
+					..Default::default()
+				}
+				.encode_to_vec(),
+				pending_response: tx,
+			})
+			.await
+			.unwrap();
+
+		assert!(rx.await.unwrap().result.is_ok());
+		assert_eq!(
+			wantlist_rx.try_next().unwrap(),
+			Some(WantlistReceived {
+				peer,
+				wantlist: ValidatedWantlist {
+					entries: vec![ValidatedEntry {
+						cid,
+						want_block: false,
+						send_dont_have: false,
+						cancel: false,
+					}],
+					ignored: 0,
+					full: false,
+				},
+			}),
+		);
+		assert!(wantlist_rx.try_next().is_err());
+	}
+
+	#[tokio::test]
+	async fn cancelled_entries_not_served() {
+		let mut client = TestClientBuilder::with_tx_storage(u32::MAX).build();
+		let mut block_builder = client.new_block(Default::default()).unwrap();
+
+		let data = [vec![0x13, 0x37, 0x13, 0x38], vec![0x13, 0x37, 0x13, 0x39]];
+		for (nonce, data) in data.iter().enumerate() {
+			let ext = ExtrinsicBuilder::new_indexed_call(data.clone()).nonce(nonce as u64).build();
+			block_builder.push(ext).unwrap();
+		}
+		let block = block_builder.build().unwrap().block;
+
+		client.import(BlockOrigin::File, block).await.unwrap();
+
+		let (bitswap, config) = BitswapRequestHandler::new(Arc::new(client));
+
+		tokio::spawn(async move { bitswap.run().await });
+
+		// Cancel one of two wanted blocks, then send only cancels.
+		for (cancel, expected_payload) in
+			[(vec![true, false], vec![data[1].clone()]), (vec![true, true], vec![])]
+		{
+			let (tx, rx) = oneshot::channel();
+			config
+				.inbound_queue
+				.clone()
+				.unwrap()
+				.send(IncomingRequest {
+					peer: PeerId::random(),
+					payload: BitswapMessage {
+						wantlist: Some(Wantlist {
+							entries: data
+								.iter()
+								.zip(cancel)
+								.map(|(data, cancel)| Entry {
+									block: cid_for(data, 0x70).to_bytes(),
+									cancel,
+									send_dont_have: true,
+									..Default::default()
+								})
+								.collect(),
+							full: false,
+						}),
+						..Default::default()
+					}
+					.encode_to_vec(),
+					pending_response: tx,
+				})
+				.await
+				.unwrap();
+
+			if let Ok(OutgoingResponse { result, .. }) = rx.await {
+				let response =
+					schema::bitswap::Message::decode(&result.expect("fetch to succeed")[..])
+						.unwrap();
+				assert_eq!(
+					response.payload.into_iter().map(|block| block.data).collect::<Vec<_>>(),
+					expected_payload,
+				);
+				assert!(response.block_presences.is_empty());
+			} else {
+				panic!("invalid event received");
+			}
+		}
+	}
+
+	/// Backend failing to look up one transaction, or all of them while `down` is set.
+	struct FlakyBackend {
+		client: TestClient,
+		failing: Option<sp_core::H256>,
+		down: Arc<AtomicBool>,
+	}
+
+	impl BlockBackend<Block> for FlakyBackend {
+		fn block_body(
+			&self,
+			hash: sp_core::H256,
+		) -> sp_blockchain::Result<Option<Vec<<Block as BlockT>::Extrinsic>>> {
+			self.client.block_body(hash)
+		}
+
+		fn block_indexed_body(
+			&self,
+			hash: sp_core::H256,
+		) -> sp_blockchain::Result<Option<Vec<Vec<u8>>>> {
+			self.client.block_indexed_body(hash)
+		}
+
+		fn block(&self, hash: sp_core::H256) -> sp_blockchain::Result<Option<SignedBlock<Block>>> {
+			self.client.block(hash)
+		}
+
+		fn block_status(
+			&self,
+			hash: sp_core::H256,
+		) -> sp_blockchain::Result<sp_consensus::BlockStatus> {
+			self.client.block_status(hash)
+		}
+
+		fn justifications(
+			&self,
+			hash: sp_core::H256,
+		) -> sp_blockchain::Result<Option<Justifications>> {
+			self.client.justifications(hash)
+		}
+
+		fn block_hash(
+			&self,
+			number: NumberFor<Block>,
+		) -> sp_blockchain::Result<Option<sp_core::H256>> {
+			self.client.block_hash(number)
+		}
+
+		fn indexed_transaction(
+			&self,
+			hash: sp_core::H256,
+		) -> sp_blockchain::Result<Option<Vec<u8>>> {
+			if self.failing == Some(hash) || self.down.load(Ordering::Relaxed) {
+				return Err(sp_blockchain::Error::Backend("Database is locked".into()))
+			}
+			self.client.indexed_transaction(hash)
+		}
+
+		fn requires_full_sync(&self) -> bool {
+			self.client.requires_full_sync()
+		}
+	}
+
+	#[tokio::test]
+	async fn backend_error_not_answered_dont_have() {
+		let mut client = TestClientBuilder::with_tx_storage(u32::MAX).build();
+		let mut block_builder = client.new_block(Default::default()).unwrap();
+
+		let data = [vec![0x13, 0x37, 0x13, 0x38], vec![0x13, 0x37, 0x13, 0x39]];
+		for (nonce, data) in data.iter().enumerate() {
+			let ext = ExtrinsicBuilder::new_indexed_call(data.clone()).nonce(nonce as u64).build();
+			block_builder.push(ext).unwrap();
+		}
+		let block = block_builder.build().unwrap().block;
+
+		client.import(BlockOrigin::File, block).await.unwrap();
+
+		let backend = FlakyBackend {
+			client,
+			failing: Some(BlakeTwo256::hash(&data[0])),
+			down: Arc::default(),
+		};
+		let (bitswap, config) = BitswapRequestHandler::new(Arc::new(backend));
+
+		tokio::spawn(async move { bitswap.run().await });
+
+		let (tx, rx) = oneshot::channel();
+		config
+			.inbound_queue
+			.unwrap()
+			.send(IncomingRequest {
+				peer: PeerId::random(),
+				payload: BitswapMessage {
+					wantlist: Some(Wantlist {
+						entries: data
+							.iter()
+							.map(|data| Entry {
+								block: cid_for(data, 0x70).to_bytes(),
+								send_dont_have: true,
+								..Default::default()
+							})
+							.collect(),
+						full: false,
+					}),
+					..Default::default()
+				}
+				.encode_to_vec(),
+				pending_response: tx,
+			})
+			.await
+			.unwrap();
+
+		if let Ok(OutgoingResponse { result, .. }) = rx.await {
+			let response =
+				schema::bitswap::Message::decode(&result.expect("fetch to succeed")[..]).unwrap();
+			assert_eq!(
+				response.payload.into_iter().map(|block| block.data).collect::<Vec<_>>(),
+				vec![data[1].clone()],
+			);
+			assert!(response.block_presences.is_empty());
+		} else {
+			panic!("invalid event received");
+		}
+	}
+
+	#[tokio::test]
+	async fn malformed_cid_does_not_block_valid_entries() {
+		let mut client = TestClientBuilder::with_tx_storage(u32::MAX).build();
+		let mut block_builder = client.new_block(Default::default()).unwrap();
+
+		let ext = ExtrinsicBuilder::new_indexed_call(vec![0x13, 0x37, 0x13, 0x38]).build();
+		block_builder.push(ext).unwrap();
+		let block = block_builder.build().unwrap().block;
+
+		client.import(BlockOrigin::File, block).await.unwrap();
+
+		let (bitswap, config) = BitswapRequestHandler::new(Arc::new(client));
+
+		tokio::spawn(async move { bitswap.run().await });
+
+		let (tx, rx) = oneshot::channel();
+		config
+			.inbound_queue
+			.unwrap()
+			.send(IncomingRequest {
+				peer: PeerId::random(),
+				payload: BitswapMessage {
+					wantlist: Some(Wantlist {
+						entries: vec![
+							Entry { block: vec![0x01, 0x70, 0xff], ..Default::default() },
+							Entry {
+								block: cid_for(&[0x13, 0x37, 0x13, 0x38], 0x70).to_bytes(),
+								..Default::default()
+							},
+						],
+						full: false,
+					}),
+					..Default::default()
+				}
+				.encode_to_vec(),
+				pending_response: tx,
+			})
+			.await
+			.unwrap();
+
+		if let Ok(OutgoingResponse { result, .. }) = rx.await {
+			let response =
+				schema::bitswap::Message::decode(&result.expect("fetch to succeed")[..]).unwrap();
+			assert_eq!(
+				response.payload.into_iter().map(|block| block.data).collect::<Vec<_>>(),
+				vec![vec![0x13, 0x37, 0x13, 0x38]],
+			);
+		} else {
+			panic!("invalid event received");
+		}
+	}
+
+	#[tokio::test]
+	async fn backend_circuit_breaker_opens_and_closes() {
+		let mut client = TestClientBuilder::with_tx_storage(u32::MAX).build();
+		let mut block_builder = client.new_block(Default::default()).unwrap();
+
+		let ext = ExtrinsicBuilder::new_indexed_call(vec![0x13, 0x37, 0x13, 0x38]).build();
+		block_builder.push(ext).unwrap();
+		let block = block_builder.build().unwrap().block;
+
+		client.import(BlockOrigin::File, block).await.unwrap();
+
+		let down = Arc::new(AtomicBool::new(true));
+		let backend = FlakyBackend { client, failing: None, down: down.clone() };
+		let (backend_status_tx, mut backend_status_rx) = mpsc::channel(16);
+		let (bitswap, config) = BitswapRequestHandler::new(Arc::new(backend));
+		let bitswap = bitswap
+			.with_backend_circuit_breaker(2, Duration::from_millis(200))
+			.with_backend_status_notifications(backend_status_tx);
+
+		tokio::spawn(async move { bitswap.run().await });
+
+		let inbound_queue = config.inbound_queue.unwrap();
+		let cid = cid_for(&[0x13, 0x37, 0x13, 0x38], 0x70);
+		let request = || async {
+			let (tx, rx) = oneshot::channel();
+			inbound_queue
+				.send(IncomingRequest {
+					peer: PeerId::random(),
+					payload: BitswapMessage {
+						wantlist: Some(Wantlist {
+							entries: vec![Entry {
+								block: cid.to_bytes(),
+								send_dont_have: true,
+								..Default::default()
+							}],
+							full: false,
+						}),
+						..Default::default()
+					}
+					.encode_to_vec(),
+					pending_response: tx,
+				})
+				.await
+				.unwrap();
+
+			let result = rx.await.unwrap().result.expect("fetch to succeed");
+			schema::bitswap::Message::decode(&result[..]).unwrap()
+		};
+
+		// Two consecutive errors open the breaker.
+		assert_eq!(request().await, BitswapMessage::default());
+		assert!(backend_status_rx.try_next().is_err());
+		assert_eq!(request().await, BitswapMessage::default());
+		assert_eq!(backend_status_rx.try_next().unwrap(), Some(BackendStatus::Degraded));
+
+		// No lookups, and no `DontHave`, during the cooldown.
+		down.store(false, Ordering::Relaxed);
+		assert_eq!(request().await, BitswapMessage::default());
+
+		// The probe after the cooldown succeeds and closes the breaker.
+		tokio::time::sleep(Duration::from_millis(300)).await;
+		let response = request().await;
+		assert_eq!(
+			response.payload.into_iter().map(|block| block.data).collect::<Vec<_>>(),
+			vec![vec![0x13, 0x37, 0x13, 0x38]],
+		);
+		assert_eq!(backend_status_rx.try_next().unwrap(), Some(BackendStatus::Recovered));
+		assert!(backend_status_rx.try_next().is_err());
+	}
+
+	#[tokio::test]
+	async fn oversized_response_not_sent() {
+		let mut client = TestClientBuilder::with_tx_storage(u32::MAX).build();
+		let mut block_builder = client.new_block(Default::default()).unwrap();
+
+		let ext = ExtrinsicBuilder::new_indexed_call(vec![0x13, 0x37, 0x13, 0x38]).build();
+		block_builder.push(ext).unwrap();
+		let block = block_builder.build().unwrap().block;
+
+		client.import(BlockOrigin::File, block).await.unwrap();
+
+		let (bitswap, config) = BitswapRequestHandler::new(Arc::new(client));
+		let bitswap = bitswap.with_max_response_size(4);
+
+		tokio::spawn(async move { bitswap.run().await });
+
+		let (tx, rx) = oneshot::channel();
+		config
+			.inbound_queue
+			.unwrap()
+			.send(IncomingRequest {
+				peer: PeerId::random(),
+				payload: BitswapMessage {
+					wantlist: Some(Wantlist {
+						entries: vec![Entry {
+							block: cid_for(&[0x13, 0x37, 0x13, 0x38], 0x70).to_bytes(),
+							..Default::default()
+						}],
+						full: false,
+					}),
+					..Default::default()
+				}
+				.encode_to_vec(),
+				pending_response: tx,
+			})
+			.await
+			.unwrap();
+
+		if let Ok(OutgoingResponse { result, reputation_changes, sent_feedback }) = rx.await {
+			assert_eq!(result, Err(()));
+			assert_eq!(reputation_changes, Vec::new());
+			assert!(sent_feedback.is_none());
+		} else {
+			panic!("invalid event received");
+		}
+	}
+
+	#[tokio::test]
+	async fn only_first_wantlist_entries_served() {
+		let mut client = TestClientBuilder::with_tx_storage(u32::MAX).build();
+		let mut block_builder = client.new_block(Default::default()).unwrap();
+
+		let data = [vec![0x13, 0x37, 0x13, 0x38], vec![0x13, 0x37, 0x13, 0x39]];
+		for (nonce, data) in data.iter().enumerate() {
+			let ext = ExtrinsicBuilder::new_indexed_call(data.clone()).nonce(nonce as u64).build();
+			block_builder.push(ext).unwrap();
+		}
+		let block = block_builder.build().unwrap().block;
+
+		client.import(BlockOrigin::File, block).await.unwrap();
+
+		let (bitswap, config) = BitswapRequestHandler::new(Arc::new(client));
+		let bitswap = bitswap.with_max_wantlist_entries_per_message(1);
+
+		tokio::spawn(async move { bitswap.run().await });
+
+		let (tx, rx) = oneshot::channel();
+		config
+			.inbound_queue
+			.unwrap()
+			.send(IncomingRequest {
+				peer: PeerId::random(),
+				payload: BitswapMessage {
+					wantlist: Some(Wantlist {
+						entries: data
+							.iter()
+							.map(|data| Entry {
+								block: cid_for(data, 0x70).to_bytes(),
+								send_dont_have: true,
+								..Default::default()
+							})
+							.collect(),
+						full: false,
+					}),
+					..Default::default()
+				}
+				.encode_to_vec(),
+				pending_response: tx,
+			})
+			.await
+			.unwrap();
+
+		if let Ok(OutgoingResponse { result, .. }) = rx.await {
+			let response =
+				schema::bitswap::Message::decode(&result.expect("fetch to succeed")[..]).unwrap();
+			assert_eq!(
+				response.payload.into_iter().map(|block| block.data).collect::<Vec<_>>(),
+				vec![data[0].clone()],
+			);
+			assert!(response.block_presences.is_empty());
+		} else {
+			panic!("invalid event received");
+		}
+	}
 }
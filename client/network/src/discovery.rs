@@ -56,9 +56,11 @@ use libp2p::{
 	core::{Endpoint, Multiaddr},
 	kad::{
 		handler::KademliaHandler,
+		kbucket::Key as KBucketKey,
 		record::store::{MemoryStore, RecordStore},
-		GetClosestPeersError, GetRecordOk, Kademlia, KademliaBucketInserts, KademliaConfig,
-		KademliaEvent, QueryId, QueryResult, Quorum, Record, RecordKey,
+		GetClosestPeersError, GetRecordOk, InboundRequest, Kademlia, KademliaBucketInserts,
+		KademliaConfig, KademliaEvent, KademliaStoreInserts, ProviderRecord, QueryId, QueryResult,
+		Quorum, Record, RecordKey,
 	},
 	mdns::{self, tokio::Behaviour as TokioMdns},
 	multiaddr::Protocol,
@@ -87,6 +89,10 @@ use std::{
 /// a given address.
 const MAX_KNOWN_EXTERNAL_ADDRESSES: usize = 32;
 
+/// Maximum number of peers with mismatching Kademlia protocols that we will cache.
+/// This only affects whether we log at debug or trace level when ignoring their addresses.
+const MAX_PEERS_WITH_PROTOCOL_MISMATCH: usize = 256;
+
 /// Default value for Kademlia replication factor which  determines to how many closest peers a
 /// record is replicated to.
 pub const DEFAULT_KADEMLIA_REPLICATION_FACTOR: usize = 20;
@@ -106,6 +112,7 @@ pub struct DiscoveryConfig {
 	kademlia_disjoint_query_paths: bool,
 	kademlia_protocols: Vec<Vec<u8>>,
 	kademlia_replication_factor: NonZeroUsize,
+	kademlia_max_provider_record_distance: Option<u32>,
 }
 
 impl DiscoveryConfig {
@@ -123,6 +130,7 @@ impl DiscoveryConfig {
 			kademlia_protocols: Vec::new(),
 			kademlia_replication_factor: NonZeroUsize::new(DEFAULT_KADEMLIA_REPLICATION_FACTOR)
 				.expect("value is a constant; constant is non-zero; qed."),
+			kademlia_max_provider_record_distance: None,
 		}
 	}
 
@@ -195,6 +203,17 @@ impl DiscoveryConfig {
 		self
 	}
 
+	/// Only store provider records from remote peers whose key is close to the local peer ID.
+	///
+	/// Records are rejected if the base 2 logarithm of the XOR distance between their key and the
+	/// local peer ID exceeds `value`. This stops peers from filling our store with records for
+	/// keys far from us, at the cost of recall: a lookup for a rejected key has to find the
+	/// record on a closer node. `None` (the default) stores all provider records.
+	pub fn with_kademlia_max_provider_record_distance(&mut self, value: Option<u32>) -> &mut Self {
+		self.kademlia_max_provider_record_distance = value;
+		self
+	}
+
 	/// Create a `DiscoveryBehaviour` from this config.
 	pub fn finish(self) -> DiscoveryBehaviour {
 		let Self {
@@ -208,6 +227,7 @@ impl DiscoveryConfig {
 			kademlia_disjoint_query_paths,
 			kademlia_protocols,
 			kademlia_replication_factor,
+			kademlia_max_provider_record_distance,
 		} = self;
 
 		let kademlia = if !kademlia_protocols.is_empty() {
@@ -220,6 +240,11 @@ impl DiscoveryConfig {
 			// auto-insertion and instead add peers manually.
 			config.set_kbucket_inserts(KademliaBucketInserts::Manual);
 			config.disjoint_query_paths(kademlia_disjoint_query_paths);
+			// Inbound records are then passed to us to be stored, so that provider records can
+			// be filtered by distance.
+			if kademlia_max_provider_record_distance.is_some() {
+				config.set_record_filtering(KademliaStoreInserts::FilterBoth);
+			}
 
 			let store = MemoryStore::new(local_peer_id);
 			let mut kad = Kademlia::with_config(local_peer_id, store, config);
@@ -265,6 +290,11 @@ impl DiscoveryConfig {
 					.expect("value is a constant; constant is non-zero; qed."),
 			),
 			records_to_publish: Default::default(),
+			kademlia_max_provider_record_distance,
+			peers_with_protocol_mismatch: LruHashSet::new(
+				NonZeroUsize::new(MAX_PEERS_WITH_PROTOCOL_MISMATCH)
+					.expect("value is a constant; constant is non-zero; qed."),
+			),
 		}
 	}
 }
@@ -308,6 +338,12 @@ pub struct DiscoveryBehaviour {
 	/// did not return the record(in `FinishedWithNoAdditionalRecord`). We will then put the record
 	/// to these peers.
 	records_to_publish: HashMap<QueryId, Record>,
+	/// Max log2 XOR distance from the local peer ID of provider records we store. If set,
+	/// inbound records are filtered and stored by us instead of by Kademlia.
+	kademlia_max_provider_record_distance: Option<u32>,
+	/// A cache of peers whose addresses were ignored because of a Kademlia protocol mismatch.
+	/// Only used for logging purposes.
+	peers_with_protocol_mismatch: LruHashSet<PeerId>,
 }
 
 impl DiscoveryBehaviour {
@@ -376,15 +412,47 @@ impl DiscoveryBehaviour {
 				);
 				kademlia.add_address(peer_id, addr.clone());
 			} else {
-				trace!(
+				let local_protocols = kademlia
+					.protocol_names()
+					.iter()
+					.map(|p| String::from_utf8_lossy(p.as_ref()).into_owned())
+					.collect::<Vec<_>>();
+				let remote_protocols = supported_protocols
+					.iter()
+					.map(|p| String::from_utf8_lossy(p.as_ref()).into_owned())
+					.collect::<Vec<_>>();
+
+				// Only log the first mismatch of a peer at debug level, it would repeat for every
+				// address the peer reports.
+				let level = if self.peers_with_protocol_mismatch.insert(*peer_id) {
+					log::Level::Debug
+				} else {
+					log::Level::Trace
+				};
+				log::log!(
 					target: "sub-libp2p",
+					level,
 					"Ignoring self-reported address {} from {} as remote node is not part of the \
-					 Kademlia DHT supported by the local node.", addr, peer_id,
+					 Kademlia DHT supported by the local node. Local protocols: {:?}, remote \
+					 protocols: {:?}.",
+					addr,
+					peer_id,
+					local_protocols,
+					remote_protocols,
 				);
 			}
 		}
 	}
 
+	/// Returns the Kademlia protocol names we support, if Kademlia is enabled.
+	///
+	/// Peers are only added to the DHT if they support one of these.
+	pub fn kademlia_protocol_names(&self) -> Vec<Vec<u8>> {
+		self.kademlia.as_ref().map_or_else(Vec::new, |kademlia| {
+			kademlia.protocol_names().iter().map(|p| p.as_ref().to_vec()).collect()
+		})
+	}
+
 	/// Start fetching a record from the DHT.
 	///
 	/// A corresponding `ValueFound` or `ValueNotFound` event will later be generated.
@@ -435,21 +503,45 @@ impl DiscoveryBehaviour {
 			.map(|kad| kad.store_mut().records().fold(0, |tot, rec| tot + rec.value.len()))
 	}
 
+	/// Store a provider record received from a remote peer, unless its key is too far from us.
+	fn store_provider_record(&mut self, record: ProviderRecord) {
+		let Some(kademlia) = self.kademlia.as_mut() else { return };
+
+		if let Some(max_distance) = self.kademlia_max_provider_record_distance {
+			let distance =
+				KBucketKey::from(self.local_peer_id).distance(&KBucketKey::new(record.key.clone()));
+			if distance.ilog2().map_or(false, |distance| distance > max_distance) {
+				debug!(
+					target: "sub-libp2p",
+					"Libp2p => Ignoring provider record for far key {:?} from {}",
+					HexDisplay::from(&record.key.to_vec()), record.provider,
+				);
+				return
+			}
+		}
+
+		if let Err(e) = kademlia.store_mut().add_provider(record) {
+			debug!(target: "sub-libp2p", "Libp2p => Failed to store provider record: {:?}", e);
+		}
+	}
+
 	/// Can the given `Multiaddr` be put into the DHT?
 	///
-	/// This test is successful only for global IP addresses and DNS names.
+	/// This test is successful only for global IP addresses and DNS names. The first IP or DNS
+	/// component of the address is checked, any protocols preceding it are ignored.
 	// NB: Currently all DNS names are allowed and no check for TLD suffixes is done
 	// because the set of valid domains is highly dynamic and would require frequent
 	// updates, for example by utilising publicsuffix.org or IANA.
 	pub fn can_add_to_dht(addr: &Multiaddr) -> bool {
-		let ip = match addr.iter().next() {
-			Some(Protocol::Ip4(ip)) => IpNetwork::from(ip),
-			Some(Protocol::Ip6(ip)) => IpNetwork::from(ip),
-			Some(Protocol::Dns(_)) | Some(Protocol::Dns4(_)) | Some(Protocol::Dns6(_)) =>
-				return true,
-			_ => return false,
-		};
-		ip.is_global()
+		for protocol in addr.iter() {
+			match protocol {
+				Protocol::Ip4(ip) => return IpNetwork::from(ip).is_global(),
+				Protocol::Ip6(ip) => return IpNetwork::from(ip).is_global(),
+				Protocol::Dns(_) | Protocol::Dns4(_) | Protocol::Dns6(_) => return true,
+				_ => {},
+			}
+		}
+		false
 	}
 }
 
@@ -737,6 +829,23 @@ impl NetworkBehaviour for DiscoveryBehaviour {
 						let ev = DiscoveryOut::Discovered(peer);
 						return Poll::Ready(ToSwarm::GenerateEvent(ev))
 					},
+					KademliaEvent::InboundRequest {
+						request: InboundRequest::AddProvider { record: Some(record) },
+					} => {
+						self.store_provider_record(record);
+					},
+					KademliaEvent::InboundRequest {
+						request: InboundRequest::PutRecord { record: Some(record), .. },
+					} =>
+						if let Some(kad) = self.kademlia.as_mut() {
+							if let Err(e) = kad.store_mut().put(record) {
+								debug!(
+									target: "sub-libp2p",
+									"Libp2p => Failed to store record: {:?}",
+									e,
+								);
+							}
+						},
 					KademliaEvent::PendingRoutablePeer { .. } |
 					KademliaEvent::InboundRequest { .. } => {
 						// We are not interested in this event at the moment.
@@ -952,7 +1061,8 @@ fn kademlia_protocol_name<Hash: AsRef<[u8]>>(genesis_hash: Hash, fork_id: Option
 #[cfg(test)]
 mod tests {
 	use super::{
-		kademlia_protocol_name, legacy_kademlia_protocol_name, DiscoveryConfig, DiscoveryOut,
+		kademlia_protocol_name, legacy_kademlia_protocol_name, DiscoveryBehaviour, DiscoveryConfig,
+		DiscoveryOut, ProviderRecord, RecordKey, RecordStore,
 	};
 	use crate::config::ProtocolId;
 	use futures::prelude::*;
@@ -964,7 +1074,7 @@ mod tests {
 		identity::Keypair,
 		noise,
 		swarm::{Executor, Swarm, SwarmBuilder, SwarmEvent},
-		yamux, Multiaddr,
+		yamux, Multiaddr, PeerId,
 	};
 	use sp_core::hash::H256;
 	use std::{collections::HashSet, pin::Pin, task::Poll};
@@ -1187,4 +1297,65 @@ mod tests {
 			);
 		}
 	}
+
+	#[test]
+	fn can_add_to_dht_checks_first_ip_or_dns_component() {
+		let can_add = |addr: &str| DiscoveryBehaviour::can_add_to_dht(&addr.parse().unwrap());
+
+		assert!(can_add("/ip4/8.8.8.8/tcp/30333"));
+		assert!(can_add("/ip6/2001:4860:4860::8888/tcp/30333/ws"));
+		assert!(can_add("/dns4/example.com/tcp/30333/ws"));
+		assert!(can_add("/dnsaddr/example.com/ip4/8.8.8.8/tcp/30333"));
+
+		assert!(!can_add("/ip4/127.0.0.1/tcp/30333"));
+		assert!(!can_add("/ip6/::1/tcp/30333/ws"));
+		assert!(!can_add("/dnsaddr/example.com/ip4/192.168.0.1/tcp/30333"));
+		assert!(!can_add("/dnsaddr/example.com"));
+		assert!(!can_add("/memory/1234"));
+	}
+
+	#[test]
+	fn kademlia_protocol_names_are_exposed() {
+		let genesis_hash = H256::from_low_u64_be(1);
+		let protocol_id = ProtocolId::from("dot");
+
+		let discovery = DiscoveryConfig::new(PeerId::random()).finish();
+		assert!(discovery.kademlia_protocol_names().is_empty());
+
+		let mut config = DiscoveryConfig::new(PeerId::random());
+		config.with_kademlia(genesis_hash, None, &protocol_id);
+		assert_eq!(
+			config.finish().kademlia_protocol_names(),
+			vec![
+				kademlia_protocol_name(genesis_hash, None),
+				legacy_kademlia_protocol_name(&protocol_id),
+			],
+		);
+	}
+
+	#[test]
+	fn far_provider_records_are_rejected() {
+		let local_peer_id = Keypair::generate_ed25519().public().to_peer_id();
+		let provider = PeerId::random();
+
+		let mut discovery = {
+			let mut config = DiscoveryConfig::new(local_peer_id);
+			config
+				.with_kademlia(H256::from_low_u64_be(1), None, &ProtocolId::from("dot"))
+				.with_kademlia_max_provider_record_distance(Some(0));
+			config.finish()
+		};
+
+		// Zero distance from the local peer ID.
+		let near_key = RecordKey::new(&local_peer_id.to_bytes());
+		let far_key = RecordKey::new(b"far");
+
+		for key in [&near_key, &far_key] {
+			discovery.store_provider_record(ProviderRecord::new(key.clone(), provider, Vec::new()));
+		}
+
+		let store = discovery.kademlia.as_mut().unwrap().store_mut();
+		assert_eq!(store.providers(&near_key).len(), 1);
+		assert!(store.providers(&far_key).is_empty());
+	}
 }
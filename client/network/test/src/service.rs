@@ -722,6 +722,41 @@ async fn ensure_boot_node_addresses_consistent_with_transport_not_memory() {
 		.start_network();
 }
 
+#[tokio::test]
+async fn self_referential_boot_node_is_ignored() {
+	let listen_addr = config::build_multiaddr![Memory(rand::random::<u64>())];
+	let secret = config::ed25519::SecretKey::generate();
+	let local_peer_id = config::NodeKeyConfig::Ed25519(config::Secret::Input(secret.clone()))
+		.into_keypair()
+		.unwrap()
+		.public()
+		.to_peer_id();
+
+	// The address doesn't match the transport, so building the network would panic if the boot
+	// node wasn't dropped.
+	let boot_node = MultiaddrWithPeerId {
+		multiaddr: config::build_multiaddr![Ip4([127, 0, 0, 1]), Tcp(0_u16)],
+		peer_id: local_peer_id,
+	};
+
+	let (node, _) = TestNetworkBuilder::new()
+		.with_config(config::NetworkConfiguration {
+			listen_addresses: vec![listen_addr],
+			transport: TransportConfig::MemoryOnly,
+			boot_nodes: vec![boot_node],
+			..config::NetworkConfiguration::new(
+				"test-node",
+				"test-client",
+				config::NodeKeyConfig::Ed25519(config::Secret::Input(secret)),
+				None,
+			)
+		})
+		.build()
+		.start_network();
+
+	assert_eq!(node.local_peer_id(), local_peer_id);
+}
+
 #[tokio::test]
 #[should_panic(expected = "don't match the transport")]
 async fn ensure_reserved_node_addresses_consistent_with_transport_memory() {
@@ -827,12 +827,6 @@ where
 		net_config.add_request_response_protocol(config);
 	}
 
-	if config.network.ipfs_server {
-		let (handler, protocol_config) = BitswapRequestHandler::new(client.clone());
-		spawn_handle.spawn("bitswap-request-handler", Some("networking"), handler.run());
-		net_config.add_request_response_protocol(protocol_config);
-	}
-
 	// create transactions protocol and add it to the list of supported protocols of
 	// `network_params`
 	let transactions_handler_proto = sc_network_transactions::TransactionsHandlerPrototype::new(
@@ -846,13 +840,16 @@ where
 	);
 	net_config.add_notification_protocol(transactions_handler_proto.set_config());
 
-	// Create `PeerStore` and initialize it with bootnode peer ids.
+	// Create `PeerStore` and initialize it with bootnode peer ids, except our own.
+	let local_peer_id =
+		net_config.network_config.node_key.clone().into_keypair()?.public().to_peer_id();
 	let peer_store = PeerStore::new(
 		net_config
 			.network_config
 			.boot_nodes
 			.iter()
 			.map(|bootnode| bootnode.peer_id)
+			.filter(|peer_id| *peer_id != local_peer_id)
 			.collect(),
 	);
 	let peer_store_handle = peer_store.handle();
@@ -879,6 +876,13 @@ where
 	let sync_service_import_queue = sync_service.clone();
 	let sync_service = Arc::new(sync_service);
 
+	if config.network.ipfs_server {
+		let (handler, protocol_config) = BitswapRequestHandler::new(client.clone());
+		let handler = handler.with_sync_oracle(sync_service.clone());
+		spawn_handle.spawn("bitswap-request-handler", Some("networking"), handler.run());
+		net_config.add_request_response_protocol(protocol_config);
+	}
+
 	let genesis_hash = client.hash(Zero::zero()).ok().flatten().expect("Genesis block exists; qed");
 	let network_params = sc_network::config::Params::<TBl> {
 		role: config.role.clone(),